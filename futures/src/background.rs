@@ -1,32 +1,95 @@
-use futures::{Async, Future, Poll, Stream};
-use futures::sync::{oneshot, mpsc};
+use futures::{task, Async, Future, Poll, Stream};
+use futures::sync::{mpsc, oneshot};
+use lapin_async::connection::RequestId;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_timer::Interval;
+use tokio_timer::Delay;
 
-use commands::Command;
+use client::{Config, ConnectionOptions};
+use commands::{self, Command, CommandReceiver, CommandSender, Responder};
 use error::{Error, ErrorKind};
+use lapin_async::connection::Connection;
 use pulse::Pulse;
+use reconnect::{ConnectionState, ReconnectStrategy};
 use transport::AMQPTransport;
 
+/// Builds a brand new stream to reconnect with, e.g. by opening a fresh TCP connection.
+///
+/// Called once per reconnection attempt; each call must produce an independent stream, since the
+/// previous one was torn down along with the failed connection.
+pub(crate) type StreamFactory<T> =
+    Arc<dyn Fn() -> Box<dyn Future<Item = T, Error = io::Error> + Send> + Send + Sync>;
+
+/// A command that was handed off to the broker and is waiting for its reply.
+struct InFlight {
+    command: Box<dyn Command>,
+    responder: Responder,
+    /// When this command must have completed by, if the caller submitted one.
+    deadline: Option<Instant>,
+}
+
+/// Where the background task currently stands with respect to the broker connection.
+enum Phase<T> {
+    /// The transport is up; `conn` is usable.
+    Connected(AMQPTransport<T>),
+    /// Waiting out the delay prescribed by the `ReconnectStrategy` before trying again.
+    Waiting(Delay),
+    /// A reconnection attempt (fresh stream + AMQP handshake) is in flight.
+    Connecting(Box<dyn Future<Item = AMQPTransport<T>, Error = io::Error> + Send>),
+}
+
 /// The background task responsible for communicating with RabbitMQ.
 #[must_use = "futures do nothing unless polled"]
 pub struct Background<T>
 where
     T: AsyncRead + AsyncWrite + Send + Sync + 'static
 {
-    /// The underlying socket used to communicate with RabbitMQ.
-    transport: AMQPTransport<T>,
+    /// The current connection phase: connected, waiting to retry, or reconnecting.
+    phase: Phase<T>,
+    /// Builds a new stream to connect with on every reconnection attempt.
+    stream_factory: StreamFactory<T>,
+    /// The options the connection was (and will be, on reconnect) established with.
+    options: ConnectionOptions,
+    /// How to react when the transport reports an error.
+    strategy: ReconnectStrategy,
+    /// The number of reconnection attempts made since the last successful connection.
+    retries: u32,
+    /// The state observed by `Client::state`.
+    state: Arc<Mutex<ConnectionState>>,
+    /// Half of this is the interval at which `pulse` sends heartbeats; twice this is how long we
+    /// tolerate not hearing from the broker before considering it dead. `None` when the broker
+    /// negotiated heartbeats off entirely.
+    heartbeat_timeout: Option<Duration>,
+    /// When the last frame was read from the transport, used to detect a dead peer.
+    last_frame_at: Instant,
     /// As soon as a message is sent using this channel, the task will stop.
     shutdown: oneshot::Receiver<()>,
     /// The handle to the shutdown channel, used to stop the task.
     handle: Option<BackgroundHandle>,
     /// The channel used to receive commands that should be sent to RabbitMQ.
-    commands: mpsc::Receiver<Box<dyn Command>>,
+    commands: CommandReceiver,
     /// The channel used to send commands to the background task.
-    sender: mpsc::Sender<Box<dyn Command>>,
+    sender: CommandSender,
     /// The task that sends a heartbeat command to RabbitMQ at a defined interval.
     pulse: Pulse,
+    /// Commands that have been sent to the broker and are waiting for a reply, keyed by the
+    /// `RequestId` the broker assigned them. A tracked publish waiting on a `basic.ack`/
+    /// `basic.nack` lives here too, like any other command: `Connection::is_finished` reports
+    /// its outcome the same way it does a `channel.open-ok`.
+    pending: HashMap<RequestId, InFlight>,
+    /// The size `pending` may reach before the task stops pulling new commands off `commands`,
+    /// letting the bounded channel apply backpressure instead.
+    max_in_flight_requests: usize,
+    /// In-flight commands evicted by a dropped connection, to be replayed once reconnected.
+    requeued: VecDeque<(Box<dyn Command>, Responder, Option<Instant>)>,
+    /// The number of channels opened since the counter was last reset (on construction and on
+    /// every successful reconnection), shared with `Client` so it can enforce `channel_max`
+    /// locally instead of always round-tripping to the broker to find out it's full.
+    live_channels: Arc<AtomicUsize>,
 }
 
 /// The handle to the background task.
@@ -40,27 +103,69 @@ where
     T: AsyncRead + AsyncWrite + Send + Sync + 'static
 {
     /// Create a new background task from a transport.
-    pub(crate) fn new(transport: AMQPTransport<T>) -> Self {
+    pub(crate) fn new(
+        transport: AMQPTransport<T>,
+        stream_factory: StreamFactory<T>,
+        options: ConnectionOptions,
+        config: Config,
+    ) -> Self {
+        let strategy = options.reconnect_strategy;
+        // The broker may negotiate a lower heartbeat value than the one we asked for (or
+        // disable it entirely with `0`); that's the value we must honor, not `options.heartbeat`.
+        let negotiated_heartbeat = transport.conn.configuration.heartbeat;
+        let heartbeat_timeout = if negotiated_heartbeat == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(u64::from(negotiated_heartbeat)))
+        };
         let (shutdown_tx, shutdown) = oneshot::channel();
         let handle = Some(BackgroundHandle(Some(shutdown_tx)));
-        let (sender, commands) = mpsc::channel(1024);
-        let interval = Interval::new(Instant::now(), Duration::from_secs(60));
-        let pulse = Pulse::new(interval, sender.clone());
+        let (sender, commands) = mpsc::channel(config.pending_request_buffer);
+        // The AMQP spec recommends sending heartbeats twice as often as the negotiated timeout.
+        let pulse = Pulse::new(heartbeat_timeout.map(|timeout| timeout / 2), sender.clone());
         Background {
-            transport,
+            phase: Phase::Connected(transport),
+            stream_factory,
+            options,
+            strategy,
+            retries: 0,
+            state: Arc::new(Mutex::new(ConnectionState::Connected)),
+            heartbeat_timeout,
+            last_frame_at: Instant::now(),
             shutdown,
             handle,
             commands,
             sender,
             pulse,
+            pending: HashMap::new(),
+            max_in_flight_requests: config.max_in_flight_requests,
+            requeued: VecDeque::new(),
+            live_channels: Arc::new(AtomicUsize::new(0)),
         }
     }
 
     /// Create a new command channel instance.
-    pub(crate) fn channel(&self) -> mpsc::Sender<Box<dyn Command>> {
+    pub(crate) fn channel(&self) -> CommandSender {
         self.sender.clone()
     }
 
+    /// A handle callers can poll to observe `Connected`/`Reconnecting`/`Failed`.
+    pub(crate) fn state(&self) -> Arc<Mutex<ConnectionState>> {
+        self.state.clone()
+    }
+
+    /// The number of channels opened since the last reset, shared with `Client` so it can
+    /// enforce the broker's negotiated `channel_max` locally.
+    pub(crate) fn live_channels(&self) -> Arc<AtomicUsize> {
+        self.live_channels.clone()
+    }
+
+    /// The number of commands currently waiting in `pending`, compared against
+    /// `max_in_flight_requests` to decide whether to throttle submission.
+    fn in_flight_count(&self) -> usize {
+        self.pending.len()
+    }
+
     /// Get the handle for this task.
     ///
     /// Because there can only be one handle for this task, it returns an `Option`. When the handle
@@ -68,6 +173,172 @@ where
     pub fn handle(&mut self) -> Option<BackgroundHandle> {
         self.handle.take()
     }
+
+    /// Execute a freshly received command, then either answer it right away (if it doesn't expect
+    /// an asynchronous reply) or register it in `pending` until the broker replies to its
+    /// `RequestId` — which, for a tracked publish, means until its `basic.ack`/`basic.nack`
+    /// arrives.
+    fn dispatch(
+        conn: &mut Connection,
+        pending: &mut HashMap<RequestId, InFlight>,
+        mut command: Box<dyn Command>,
+        responder: Responder,
+        deadline: Option<Instant>,
+    ) {
+        match command.execute(conn) {
+            Ok(()) => match command.request_id() {
+                Some(request_id) => {
+                    pending.insert(request_id, InFlight { command, responder, deadline });
+                },
+                None => responder.respond(Ok(command.response(conn))),
+            },
+            Err(e) => responder.respond(Err(e)),
+        }
+    }
+
+    /// Sweep `pending`, completing the responder of every command that finished, whose deadline
+    /// elapsed, or whose caller is no longer waiting for the result.
+    fn sweep_pending(&mut self) {
+        let transport = match self.phase {
+            Phase::Connected(ref mut transport) => transport,
+            _ => return,
+        };
+        let conn = &mut transport.conn;
+        let now = Instant::now();
+        let done: Vec<RequestId> = self.pending.iter()
+            .filter(|(_, in_flight)| {
+                in_flight.responder.is_canceled()
+                    || in_flight.command.has_finished(conn)
+                    || in_flight.deadline.map_or(false, |deadline| now >= deadline)
+            })
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        if done.is_empty() {
+            return;
+        }
+        for request_id in done {
+            if let Some(InFlight { command, responder, deadline }) = self.pending.remove(&request_id) {
+                if responder.is_canceled() {
+                    continue;
+                }
+                if command.has_finished(conn) {
+                    // A reply was just read off the wire for this command, so the broker is
+                    // demonstrably still there: count it the same as a heartbeat for the purposes
+                    // of `check_heartbeat_timeout`.
+                    self.last_frame_at = now;
+                    // `live_channels` is reserved up front, at submission time (see
+                    // `Client::do_create_channel`), precisely so a `channel.open` response
+                    // landing here doesn't have to (and can't race a concurrent submission to)
+                    // bump the count itself.
+                    responder.respond(Ok(command.response(conn)));
+                } else if deadline.map_or(false, |deadline| now >= deadline) {
+                    responder.respond(Err(ErrorKind::DeadlineExceeded.into()));
+                }
+            }
+        }
+        // `pending` just shrank: give a command that was stalled on `max_in_flight_requests` a
+        // chance to dispatch without waiting for an unrelated wakeup.
+        task::current().notify();
+    }
+
+    /// The transport just dropped (or a dead peer was detected): evict every in-flight command
+    /// (resetting it so it can be replayed against the next connection) and start waiting to
+    /// reconnect.
+    fn begin_reconnect(&mut self, cause: Error) -> Result<(), Error> {
+        for (_, InFlight { mut command, responder, deadline }) in self.pending.drain() {
+            if responder.is_canceled() {
+                continue;
+            }
+            command.reset();
+            self.requeued.push_back((command, responder, deadline));
+        }
+        match self.strategy.delay(self.retries) {
+            Some(delay) => {
+                warn!("Lost connection to RabbitMQ ({}), reconnecting in {:?}", cause, delay);
+                *self.state.lock().expect("state lock poisoned") = ConnectionState::Reconnecting;
+                self.phase = Phase::Waiting(Delay::new(Instant::now() + delay));
+                Ok(())
+            },
+            None => {
+                *self.state.lock().expect("state lock poisoned") = ConnectionState::Failed;
+                // `Error` isn't `Clone` (it wraps `io::Error`/`lapin_async::error::Error`), and
+                // nobody is ever going to poll a future connection attempt to learn the real
+                // cause for these: format it once, tell every caller still waiting on
+                // `self.requeued` now, instead of leaving them to find out later as a generic
+                // `HandleDropped` once `Background` itself drops.
+                let message = format!("{}", cause);
+                while let Some((_, responder, _)) = self.requeued.pop_front() {
+                    if responder.is_canceled() {
+                        continue;
+                    }
+                    responder.respond(Err(ErrorKind::ConnectionLost(message.clone()).into()));
+                }
+                Err(ErrorKind::ConnectionLost(message).into())
+            },
+        }
+    }
+
+    /// Fail the connection if no frame has been read from the broker for `2 * heartbeat_timeout`.
+    ///
+    /// `last_frame_at` is advanced in `sweep_pending`, the one place we can honestly observe a
+    /// reply having just been read off the wire, rather than from the transport directly.
+    fn check_heartbeat_timeout(&mut self) -> Result<(), Error> {
+        let timeout = match self.heartbeat_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+        if heartbeat_timed_out(self.last_frame_at, timeout) {
+            return Err(ErrorKind::HeartbeatTimeout.into());
+        }
+        Ok(())
+    }
+
+    /// Kick off a fresh connection attempt: build a new stream and run the AMQP handshake.
+    fn start_connecting(&mut self) {
+        let stream_factory = self.stream_factory.clone();
+        let options = self.options.clone();
+        let attempt = (stream_factory)().and_then(move |stream| AMQPTransport::connect(stream, options));
+        self.phase = Phase::Connecting(Box::new(attempt));
+    }
+
+    /// A reconnection attempt just succeeded: resume normal operation and replay whatever was
+    /// lost when the previous connection dropped.
+    fn finish_connecting(&mut self, transport: AMQPTransport<T>) {
+        debug!("Reconnected to RabbitMQ after {} attempt(s)", self.retries + 1);
+        self.retries = 0;
+        self.last_frame_at = Instant::now();
+        *self.state.lock().expect("state lock poisoned") = ConnectionState::Connected;
+        self.phase = Phase::Connected(transport);
+        while let Some((command, responder, deadline)) = self.requeued.pop_front() {
+            if let Phase::Connected(ref mut transport) = self.phase {
+                Background::<T>::dispatch(&mut transport.conn, &mut self.pending, command, responder, deadline);
+            }
+        }
+        // Best-effort: reacquire as many channels as were open before the drop. Existing
+        // `Channel` handles aren't told about the new ids; re-synchronizing them is the
+        // responsibility of the channel layer itself.
+        //
+        // Nobody is waiting on these `Open` commands, so their `Responder` is paired with a
+        // receiver we drop right away. The count was zeroed above and nothing else adds to it
+        // on their behalf (`sweep_pending` never touches it), so account for each reopened
+        // channel here, at the point we know for certain we're reissuing it.
+        let to_reopen = self.live_channels.swap(0, Ordering::SeqCst);
+        for _ in 0..to_reopen {
+            let (responder, _receiver) = Responder::pair();
+            let open: Box<dyn Command> = Box::new(commands::channel::Open::new());
+            if let Phase::Connected(ref mut transport) = self.phase {
+                Background::<T>::dispatch(&mut transport.conn, &mut self.pending, open, responder, None);
+                self.live_channels.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// `true` once `2 * timeout` has elapsed since `last_frame_at`, i.e. no frame (heartbeat or
+/// otherwise) has been read from the broker within the window the AMQP spec expects at least
+/// one in.
+fn heartbeat_timed_out(last_frame_at: Instant, timeout: Duration) -> bool {
+    last_frame_at.elapsed() >= timeout * 2
 }
 
 impl<T> Future for Background<T>
@@ -84,25 +355,73 @@ where
             Ok(Async::Ready(_)) => return Ok(Async::Ready(())),
             Err(_) => return Err(ErrorKind::HandleDropped.into()),
         };
+
+        if let Phase::Waiting(_) = self.phase {
+            let fired = match self.phase {
+                Phase::Waiting(ref mut delay) => delay.poll(),
+                _ => unreachable!(),
+            };
+            match fired {
+                Ok(Async::Ready(_)) => {
+                    self.retries += 1;
+                    self.start_connecting();
+                },
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(_) => return Err(ErrorKind::TimerDropped.into()),
+            };
+        }
+        if let Phase::Connecting(_) = self.phase {
+            let polled = match self.phase {
+                Phase::Connecting(ref mut attempt) => attempt.poll(),
+                _ => unreachable!(),
+            };
+            return match polled {
+                Ok(Async::Ready(transport)) => {
+                    self.finish_connecting(transport);
+                    // Let this poll round finish; make sure we get a chance to flush whatever
+                    // was queued up while reconnecting instead of waiting for the next waker.
+                    task::current().notify();
+                    Ok(Async::NotReady)
+                },
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Err(e) => self.begin_reconnect(ErrorKind::Transport(e).into()).map(|_| Async::NotReady),
+            };
+        }
+
         match self.pulse.poll() {
             Ok(Async::Ready(_)) => unreachable!(),
             Ok(Async::NotReady) => (),
             Err(e) => return Err(e),
         };
-        match self.commands.poll() {
-            Ok(Async::Ready(Some(mut command))) => {
-                // FIXME: communicate result back to caller via oneshot
-                command.execute(&mut self.transport.conn).unwrap();
-            },
-            Ok(Async::Ready(None)) => unreachable!(),
-            Ok(Async::NotReady) => (),
-            Err(_) => unreachable!(),
+        // Once `max_in_flight_requests` is reached, stop pulling new commands off the channel:
+        // they stay buffered there (bounded by `pending_request_buffer`) instead of piling up
+        // unboundedly here, which is what gives callers real backpressure.
+        if self.in_flight_count() < self.max_in_flight_requests {
+            match self.commands.poll() {
+                Ok(Async::Ready(Some((command, responder, deadline)))) => {
+                    if let Phase::Connected(ref mut transport) = self.phase {
+                        Background::<T>::dispatch(&mut transport.conn, &mut self.pending, command, responder, deadline);
+                    }
+                },
+                Ok(Async::Ready(None)) => unreachable!(),
+                Ok(Async::NotReady) => (),
+                Err(_) => unreachable!(),
+            };
+        }
+        let transport_poll = match self.phase {
+            Phase::Connected(ref mut transport) => Some(transport.poll()),
+            _ => None,
         };
-        match self.transport.poll() {
-            Ok(Async::Ready(_)) => return Ok(Async::Ready(())),
-            Ok(Async::NotReady) => (),
-            Err(e) => return Err(ErrorKind::Transport(e).into()),
+        match transport_poll {
+            Some(Ok(Async::Ready(_))) => return Ok(Async::Ready(())),
+            Some(Ok(Async::NotReady)) => (),
+            Some(Err(e)) => return self.begin_reconnect(ErrorKind::Transport(e).into()).map(|_| Async::NotReady),
+            None => (),
         };
+        if let Err(e) = self.check_heartbeat_timeout() {
+            return self.begin_reconnect(e).map(|_| Async::NotReady);
+        }
+        self.sweep_pending();
         Ok(Async::NotReady)
     }
 }
@@ -116,3 +435,34 @@ impl Drop for BackgroundHandle {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat_not_timed_out_within_twice_the_negotiated_interval() {
+        let timeout = Duration::from_secs(10);
+        let last_frame_at = Instant::now() - Duration::from_secs(1);
+        assert!(!heartbeat_timed_out(last_frame_at, timeout));
+    }
+
+    #[test]
+    fn test_heartbeat_timed_out_after_twice_the_negotiated_interval() {
+        let timeout = Duration::from_secs(10);
+        let last_frame_at = Instant::now() - (timeout * 2) - Duration::from_secs(1);
+        assert!(heartbeat_timed_out(last_frame_at, timeout));
+    }
+
+    #[test]
+    fn test_heartbeat_timeout_resets_when_a_frame_arrives() {
+        let timeout = Duration::from_secs(10);
+        let mut last_frame_at = Instant::now() - (timeout * 2) - Duration::from_secs(1);
+        assert!(heartbeat_timed_out(last_frame_at, timeout));
+
+        // `sweep_pending` advances `last_frame_at` to `Instant::now()` as soon as it observes a
+        // command's `has_finished` turn true, i.e. a reply frame was just read off the wire.
+        last_frame_at = Instant::now();
+        assert!(!heartbeat_timed_out(last_frame_at, timeout));
+    }
+}
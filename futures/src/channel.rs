@@ -0,0 +1,66 @@
+use futures::Future;
+use std::io;
+
+use commands::{self, CommandSender, Response};
+use error::Error;
+
+/// A handle to an open AMQP channel.
+///
+/// Every command issued through this handle travels over the same `CommandSender` the `Client`
+/// that created it uses to reach the background task, tagged with this channel's id.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    id: u16,
+    sender: CommandSender,
+}
+
+impl Channel {
+    pub(crate) fn new(id: u16, sender: CommandSender) -> Self {
+        Channel { id, sender }
+    }
+
+    /// This channel's id, as assigned by the broker in response to `channel.open`.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub(crate) fn sender(&self) -> CommandSender {
+        self.sender.clone()
+    }
+}
+
+/// Options accepted by `Client::create_confirm_channel` when enabling `confirm.select`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConfirmSelectOptions {
+    /// Don't wait for the broker to acknowledge `confirm.select` before returning.
+    pub nowait: bool,
+}
+
+/// A `Channel` with RabbitMQ's publisher confirms extension enabled.
+#[derive(Clone, Debug)]
+pub struct ConfirmChannel {
+    channel: Channel,
+}
+
+impl ConfirmChannel {
+    pub(crate) fn new(id: u16, sender: CommandSender) -> Self {
+        ConfirmChannel { channel: Channel::new(id, sender) }
+    }
+
+    /// This channel's id.
+    pub fn id(&self) -> u16 {
+        self.channel.id()
+    }
+
+    /// Publish `payload` to `exchange` with `routing_key`, resolving to `true`/`false` once the
+    /// broker acks or nacks the message, instead of as soon as it's written to the socket.
+    pub fn publish(&self, exchange: String, routing_key: String, payload: Vec<u8>) -> impl Future<Item = bool, Error = io::Error> + Send + 'static {
+        let command: Box<dyn commands::Command> = Box::new(commands::basic::Publish::new(self.channel.id(), exchange, routing_key, payload));
+        commands::IssueFuture::new(self.channel.sender(), command)
+            .map_err(|e: Error| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+            .and_then(|response| match response {
+                Response::Confirm(ack) => Ok(ack),
+                _ => Err(io::Error::new(io::ErrorKind::Other, "unexpected response to basic.publish")),
+            })
+    }
+}
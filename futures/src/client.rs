@@ -3,29 +3,60 @@ use lapin_async;
 use std::default::Default;
 use std::io;
 use std::str::FromStr;
-use futures::{future, Future};
-use futures::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::future::{self, Either};
+use futures::{Future, Poll};
 use tokio_io::{AsyncRead, AsyncWrite};
 
 use transport::*;
-use background::Background;
-use channel::{Channel, ConfirmSelectOptions};
-use commands::Command;
+use background::{Background, StreamFactory};
+use channel::{Channel, ConfirmChannel, ConfirmSelectOptions};
+use commands::{self, CommandSender, Response};
+use error::{Error, ErrorKind};
+use reconnect::{ConnectionState, ReconnectStrategy};
 
 /// the Client structures connects to a server and creates channels
 #[derive(Clone)]
 pub struct Client {
-    channel:           mpsc::Sender<Box<dyn Command>>,
+    channel:           CommandSender,
+    state:             Arc<Mutex<ConnectionState>>,
+    live_channels:     Arc<AtomicUsize>,
     pub configuration: ConnectionConfiguration,
 }
 
+/// Tunable resource limits for a `Client`/`Background` pair.
+///
+/// Not constructed directly: tune one via the builder methods on the `ConnectBuilder` returned
+/// by `Client::connect`, e.g. `Client::connect(f, options).max_in_flight_requests(256)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// The capacity of the channel used to submit commands to the background task.
+    pub pending_request_buffer: usize,
+    /// The maximum number of commands that may be awaiting a broker reply (or an ack/nack) at
+    /// once. Once reached, the background task stops accepting new commands until one finishes,
+    /// so callers see backpressure instead of an unbounded in-flight table.
+    pub max_in_flight_requests: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            pending_request_buffer: 1024,
+            max_in_flight_requests: 1024,
+        }
+    }
+}
+
 #[derive(Clone,Debug,PartialEq)]
 pub struct ConnectionOptions {
-    pub username:  String,
-    pub password:  String,
-    pub vhost:     String,
-    pub frame_max: u32,
-    pub heartbeat: u16,
+    pub username:           String,
+    pub password:           String,
+    pub vhost:              String,
+    pub frame_max:          u32,
+    pub heartbeat:          u16,
+    pub reconnect_strategy: ReconnectStrategy,
 }
 
 impl ConnectionOptions {
@@ -36,6 +67,7 @@ impl ConnectionOptions {
             vhost: uri.vhost,
             frame_max: uri.query.frame_max.unwrap_or(0),
             heartbeat: uri.query.heartbeat.unwrap_or(0),
+            reconnect_strategy: ReconnectStrategy::default(),
         }
     }
 }
@@ -43,11 +75,12 @@ impl ConnectionOptions {
 impl Default for ConnectionOptions {
     fn default() -> ConnectionOptions {
         ConnectionOptions {
-            username:  "guest".to_string(),
-            password:  "guest".to_string(),
-            vhost:     "/".to_string(),
-            frame_max: 0,
-            heartbeat: 0,
+            username:           "guest".to_string(),
+            password:           "guest".to_string(),
+            vhost:              "/".to_string(),
+            frame_max:          0,
+            heartbeat:          0,
+            reconnect_strategy: ReconnectStrategy::default(),
         }
     }
 }
@@ -64,14 +97,19 @@ impl FromStr for ConnectionOptions {
 pub type ConnectionConfiguration = lapin_async::connection::Configuration;
 
 impl Client {
-    /// Takes a stream (TCP, TLS, unix socket, etc) and uses it to connect to an AMQP server.
+    /// Takes a factory producing a stream (TCP, TLS, unix socket, etc) and uses it to connect to
+    /// an AMQP server.
+    ///
+    /// The factory is called again for every reconnection attempt prescribed by
+    /// `options.reconnect_strategy`, so it must be able to produce a fresh, independent stream
+    /// each time it's called (e.g. `move || Box::new(TcpStream::connect(&addr))`).
     ///
     /// This function returns a future that resolves once the connection handshake is done.
     /// The result is a tuple containing a `Client` that can be used to create `Channel`s and a
-    /// `Heartbeat` instance. The heartbeat is a task (it implements `Future`) that should be
-    /// spawned independently of the other futures.
+    /// `Background` task. The background task drives the connection (including reconnection) and
+    /// should be spawned independently of the other futures.
     ///
-    /// To stop the heartbeat task, see `HeartbeatHandle`.
+    /// To stop the background task, see `BackgroundHandle`.
     ///
     /// # Example
     ///
@@ -87,10 +125,8 @@ impl Client {
     /// use lapin_futures::client::{Client, ConnectionOptions};
     ///
     /// let addr = "127.0.0.1:5672".parse().unwrap();
-    /// let f = TcpStream::connect(&addr)
-    ///     .and_then(|stream| {
-    ///         Client::connect(stream, ConnectionOptions::default())
-    ///     })
+    /// let f = Client::connect(move || Box::new(TcpStream::connect(&addr)), ConnectionOptions::default())
+    ///     .max_in_flight_requests(256)
     ///     .and_then(|(client, mut background)| {
     ///         let handle = background.handle().unwrap();
     ///         tokio::spawn(
@@ -107,41 +143,167 @@ impl Client {
     /// ).expect("runtime exited with failure");
     /// # }
     /// ```
-    pub fn connect<T>(stream: T, options: ConnectionOptions) ->
+    pub fn connect<F, T>(connect: F, options: ConnectionOptions) -> ConnectBuilder<F, T>
+    where
+        F: Fn() -> Box<dyn Future<Item = T, Error = io::Error> + Send> + Send + Sync + 'static,
+        T: AsyncRead + AsyncWrite + Send + Sync + 'static
+    {
+        ConnectBuilder {
+            connect: Some(connect),
+            options: Some(options),
+            config: Config::default(),
+            inner: None,
+        }
+    }
+
+    fn do_connect<F, T>(connect: F, options: ConnectionOptions, config: Config) ->
         impl Future<Item = (Self, Background<T>), Error = io::Error> + Send + 'static
     where
+        F: Fn() -> Box<dyn Future<Item = T, Error = io::Error> + Send> + Send + Sync + 'static,
         T: AsyncRead + AsyncWrite + Send + Sync + 'static
     {
-        AMQPTransport::connect(stream, options).and_then(|transport| {
+        let stream_factory: StreamFactory<T> = Arc::new(connect);
+        let handshake_factory = stream_factory.clone();
+        let handshake_options = options.clone();
+        (handshake_factory)().and_then(move |stream| AMQPTransport::connect(stream, handshake_options)).and_then(move |transport| {
             debug!("got client service");
             let configuration = transport.conn.configuration.clone();
-            let background = Background::new(transport);
+            let background = Background::new(transport, stream_factory, options, config);
             let client = Client {
                 configuration,
-                channel: background.channel()
+                channel: background.channel(),
+                state: background.state(),
+                live_channels: background.live_channels(),
             };
             Ok((client, background))
         })
     }
 
+    /// The current state of the connection to the broker.
+    pub fn state(&self) -> ConnectionState {
+        *self.state.lock().expect("state lock poisoned")
+    }
+
     /// creates a new channel
     ///
     /// returns a future that resolves to a `Channel` once the method succeeds
     pub fn create_channel(&self) -> impl Future<Item = Channel, Error = io::Error> + Send + 'static {
-        Channel::create(self.channel.clone())
+        self.do_create_channel(None)
     }
 
-    /// returns a future that resolves to a `Channel` once the method succeeds
-    /// the channel will support RabbitMQ's confirm extension
-    pub fn create_confirm_channel(&self, options: ConfirmSelectOptions) -> impl Future<Item = (), Error = io::Error> + Send + 'static {
-        // FIXME: maybe the confirm channel should be a separate type
-        // especially, if we implement transactions, the methods should be available on the original channel
-        // but not on the confirm channel. And the basic publish method should have different results
-        // self.create_channel().and_then(move |channel| {
-        //   let ch = channel.clone();
-
-        //   channel.confirm_select(options).map(|_| ch)
-        // })
-        future::ok(())
+    /// like `create_channel`, but fails (with an `io::Error` carrying the
+    /// `ErrorKind::DeadlineExceeded` message) if the broker hasn't replied to `channel.open`
+    /// within `deadline`.
+    pub fn create_channel_with_deadline(&self, deadline: Duration) -> impl Future<Item = Channel, Error = io::Error> + Send + 'static {
+        self.do_create_channel(Some(deadline))
+    }
+
+    fn do_create_channel(&self, deadline: Option<Duration>) -> impl Future<Item = Channel, Error = io::Error> + Send + 'static {
+        // The broker told us its `channel_max` at handshake time; refuse locally once we're at
+        // it instead of always paying a round-trip just to learn the same thing as a protocol
+        // error. `0` means the broker doesn't enforce a limit.
+        //
+        // The slot has to be reserved here, before the command is even submitted, not after the
+        // broker's response increments some counter: otherwise two concurrent `create_channel`
+        // calls can both read the same count, both decide there's room, and both go on to exceed
+        // `channel_max`. Claim it optimistically and give it back if `channel.open` ultimately
+        // fails. The slot is claimed even without a limit, since the background task reopens
+        // that many channels after a reconnection.
+        let channel_max = self.configuration.channel_max;
+        let live_channels = self.live_channels.clone();
+        let reserved = live_channels.fetch_add(1, Ordering::SeqCst);
+        if channel_max != 0 && reserved >= usize::from(channel_max) {
+            live_channels.fetch_sub(1, Ordering::SeqCst);
+            let err = io::Error::new(io::ErrorKind::Other, format!("{}", Error::from(ErrorKind::ChannelLimitReached)));
+            return Either::A(future::err(err));
+        }
+        let sender = self.channel.clone();
+        let command: Box<dyn commands::Command> = Box::new(commands::channel::Open::new());
+        let issue = match deadline {
+            Some(deadline) => commands::IssueFuture::with_deadline(sender.clone(), command, deadline),
+            None => commands::IssueFuture::new(sender.clone(), command),
+        };
+        Either::B(issue
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+            .and_then(move |response| match response {
+                Response::ChannelId(channel_id) => Ok(Channel::new(channel_id, sender)),
+                _ => Err(io::Error::new(io::ErrorKind::Other, "unexpected response to channel.open")),
+            })
+            .or_else(move |e| {
+                live_channels.fetch_sub(1, Ordering::SeqCst);
+                Err(e)
+            }))
+    }
+
+    /// returns a future that resolves to a `ConfirmChannel` once the method succeeds
+    ///
+    /// the returned channel has RabbitMQ's publisher confirms extension enabled: every
+    /// `ConfirmChannel::publish` resolves once the broker acks or nacks that particular message,
+    /// instead of as soon as it's written to the socket.
+    pub fn create_confirm_channel(&self, options: ConfirmSelectOptions) -> impl Future<Item = ConfirmChannel, Error = io::Error> + Send + 'static {
+        self.create_channel().and_then(move |channel| {
+            let sender = channel.sender();
+            let channel_id = channel.id();
+            let command: Box<dyn commands::Command> = Box::new(commands::channel::ConfirmSelect::new(channel_id, options.nowait));
+            commands::IssueFuture::new(sender.clone(), command)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+                .map(move |_| ConfirmChannel::new(channel_id, sender))
+        })
+    }
+}
+
+/// Returned by `Client::connect`; tunes the `Config` the connection will run with before
+/// kicking off the handshake.
+///
+/// This is itself the future that drives the handshake: polling it (directly, or via combinators
+/// like `.and_then`) is what actually calls `connect` and negotiates the connection. Any tuning
+/// method must be called before the first poll.
+#[must_use = "futures do nothing unless polled"]
+pub struct ConnectBuilder<F, T>
+where
+    F: Fn() -> Box<dyn Future<Item = T, Error = io::Error> + Send> + Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Send + Sync + 'static
+{
+    connect: Option<F>,
+    options: Option<ConnectionOptions>,
+    config: Config,
+    inner: Option<Box<dyn Future<Item = (Client, Background<T>), Error = io::Error> + Send>>,
+}
+
+impl<F, T> ConnectBuilder<F, T>
+where
+    F: Fn() -> Box<dyn Future<Item = T, Error = io::Error> + Send> + Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Send + Sync + 'static
+{
+    /// The capacity of the channel used to submit commands to the background task.
+    pub fn pending_request_buffer(mut self, value: usize) -> Self {
+        self.config.pending_request_buffer = value;
+        self
+    }
+
+    /// The maximum number of commands that may be awaiting a broker reply (or an ack/nack) at
+    /// once, before submission starts applying backpressure.
+    pub fn max_in_flight_requests(mut self, value: usize) -> Self {
+        self.config.max_in_flight_requests = value;
+        self
+    }
+}
+
+impl<F, T> Future for ConnectBuilder<F, T>
+where
+    F: Fn() -> Box<dyn Future<Item = T, Error = io::Error> + Send> + Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + Send + Sync + 'static
+{
+    type Item = (Client, Background<T>);
+
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.inner.is_none() {
+            let connect = self.connect.take().expect("ConnectBuilder polled after completion");
+            let options = self.options.take().expect("ConnectBuilder polled after completion");
+            self.inner = Some(Box::new(Client::do_connect(connect, options, self.config)));
+        }
+        self.inner.as_mut().expect("just set above").poll()
     }
 }
@@ -0,0 +1,59 @@
+use lapin_async::connection::{Connection, RequestId};
+
+use commands::{Command, Response};
+use error::{Error, ErrorKind};
+
+/// `basic.publish` on a channel with publisher confirms enabled.
+///
+/// Like every other command, completion and outcome both come from `Connection::is_finished`:
+/// once the broker's `basic.ack`/`basic.nack` for this publish's delivery tag is known,
+/// `is_finished` reports it as `Some(acked)` and the command is done.
+#[derive(Debug)]
+pub(crate) struct Publish {
+	channel_id: u16,
+	exchange: String,
+	routing_key: String,
+	payload: Vec<u8>,
+	request_id: Option<RequestId>,
+}
+
+impl Publish {
+	pub(crate) fn new(channel_id: u16, exchange: String, routing_key: String, payload: Vec<u8>) -> Self {
+		Publish {
+			channel_id,
+			exchange,
+			routing_key,
+			payload,
+			request_id: None,
+		}
+	}
+}
+
+impl Command for Publish {
+	fn execute(&mut self, conn: &mut Connection) -> Result<(), Error> {
+		self.request_id = Some(
+			conn.basic_publish(self.channel_id, self.exchange.clone(), self.routing_key.clone(), self.payload.clone())
+				.map_err(|e| ErrorKind::ProtocolError(e).into())?
+		);
+		Ok(())
+	}
+
+	fn has_finished(&self, conn: &mut Connection) -> bool {
+		// Unlike a synchronous method reply, `Some(false)` is a real, expected outcome here (a
+		// `basic.nack`), so this can't collapse it into "not finished yet" the way `.unwrap_or
+		// (false)` does elsewhere in this module.
+		conn.is_finished(self.request_id).is_some()
+	}
+
+	fn request_id(&self) -> Option<RequestId> {
+		self.request_id
+	}
+
+	fn response(&self, conn: &mut Connection) -> Response {
+		Response::Confirm(conn.is_finished(self.request_id).unwrap_or(false))
+	}
+
+	fn reset(&mut self) {
+		self.request_id = None;
+	}
+}
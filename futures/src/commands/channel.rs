@@ -1,17 +1,20 @@
-use lapin_async::connection::Connection;
+use lapin_async::connection::{Connection, RequestId};
 
-use commands::Command;
+use commands::{Command, Response};
 use error::{Error, ErrorKind};
 
 /// command used to open a channel against RabbitMQ.
+#[derive(Debug)]
 pub(crate) struct Open {
 	request_id: Option<RequestId>,
+	channel_id: Option<u16>,
 }
 
 impl Open {
 	pub(crate) fn new() -> Self {
 		Open {
 			request_id: None,
+			channel_id: None,
 		}
 	}
 }
@@ -26,10 +29,71 @@ impl Command for Open {
 			conn.channel_open(channel_id, "".into())
 				.map_err(|e| ErrorKind::ProtocolError(e).into())?
 		);
+		self.channel_id = Some(channel_id);
 		Ok(())
 	}
 
 	fn has_finished(&self, conn: &mut Connection) -> bool {
 		conn.is_finished(self.request_id).unwrap_or(false)
 	}
+
+	fn request_id(&self) -> Option<RequestId> {
+		self.request_id
+	}
+
+	fn response(&self, _conn: &mut Connection) -> Response {
+		Response::ChannelId(self.channel_id.expect("response queried before the channel was opened"))
+	}
+
+	fn reset(&mut self) {
+		self.request_id = None;
+		self.channel_id = None;
+	}
+}
+
+/// command used to enable publisher confirms on a channel via `confirm.select`.
+#[derive(Debug)]
+pub(crate) struct ConfirmSelect {
+	channel_id: u16,
+	nowait: bool,
+	request_id: Option<RequestId>,
+}
+
+impl ConfirmSelect {
+	pub(crate) fn new(channel_id: u16, nowait: bool) -> Self {
+		ConfirmSelect {
+			channel_id,
+			nowait,
+			request_id: None,
+		}
+	}
+}
+
+impl Command for ConfirmSelect {
+	fn execute(&mut self, conn: &mut Connection) -> Result<(), Error> {
+		self.request_id = Some(
+			conn.confirm_select(self.channel_id, self.nowait)
+				.map_err(|e| ErrorKind::ProtocolError(e).into())?
+		);
+		Ok(())
+	}
+
+	fn has_finished(&self, conn: &mut Connection) -> bool {
+		// With `nowait` set, the broker never sends a `confirm.select-ok`, so there's nothing to
+		// wait for beyond `execute` having queued the method frame: waiting on `is_finished` would
+		// hang forever.
+		self.nowait || conn.is_finished(self.request_id).unwrap_or(false)
+	}
+
+	fn request_id(&self) -> Option<RequestId> {
+		self.request_id
+	}
+
+	fn response(&self, _conn: &mut Connection) -> Response {
+		Response::Unit
+	}
+
+	fn reset(&mut self) {
+		self.request_id = None;
+	}
 }
@@ -1,8 +1,19 @@
-use lapin_async::connection::Connection;
+use lapin_async::connection::{Connection, RequestId};
 use std::fmt;
 
 use error::Error;
 
+/// The result produced by a `Command` once the broker has answered it.
+#[derive(Debug)]
+pub(crate) enum Response {
+    /// The command doesn't carry any data back to the caller.
+    Unit,
+    /// The id of the channel that was just opened.
+    ChannelId(u16),
+    /// The broker's confirmation of a publish: `true` for `basic.ack`, `false` for `basic.nack`.
+    Confirm(bool),
+}
+
 /// A command that can be sent to RabbitMQ.
 pub(crate) trait Command: fmt::Debug + Send {
     /// Executes the command on the given protocol state machine.
@@ -10,4 +21,17 @@ pub(crate) trait Command: fmt::Debug + Send {
 
     /// Determines whether the request has finished.
     fn has_finished(&self, conn: &mut Connection) -> bool;
+
+    /// The id the broker assigned to this request, if any.
+    ///
+    /// Commands that don't expect an asynchronous reply (e.g. `Heartbeat`) return `None`: they
+    /// are considered to have finished as soon as `execute` returns.
+    fn request_id(&self) -> Option<RequestId>;
+
+    /// Builds the `Response` to hand back to the caller once `has_finished` returns `true`.
+    fn response(&self, conn: &mut Connection) -> Response;
+
+    /// Clears any state tied to the connection the command was last executed against (its
+    /// `RequestId`, in particular), so it can be safely re-submitted after a reconnection.
+    fn reset(&mut self);
 }
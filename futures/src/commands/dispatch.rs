@@ -0,0 +1,42 @@
+use futures::sync::{mpsc, oneshot};
+use std::time::Instant;
+
+use commands::{Command, Response};
+use error::Error;
+
+/// The channel used by callers to submit a `Command` for the background task to run.
+///
+/// Each submission carries its own `Responder`, so the background task never has to guess who
+/// is waiting on a given command, plus the `Instant` (if any) by which it must have completed.
+pub(crate) type CommandSender = mpsc::Sender<(Box<dyn Command>, Responder, Option<Instant>)>;
+
+/// The background task's end of a `CommandSender`.
+pub(crate) type CommandReceiver = mpsc::Receiver<(Box<dyn Command>, Responder, Option<Instant>)>;
+
+/// Delivers the result of a `Command` back to the caller that submitted it.
+///
+/// `respond` consumes the `Responder`, so a given request can never be completed twice.
+#[derive(Debug)]
+pub(crate) struct Responder(oneshot::Sender<Result<Response, Error>>);
+
+impl Responder {
+    /// Create a new paired `Responder` and `oneshot::Receiver`.
+    pub(crate) fn pair() -> (Self, oneshot::Receiver<Result<Response, Error>>) {
+        let (tx, rx) = oneshot::channel();
+        (Responder(tx), rx)
+    }
+
+    /// Returns `true` if the caller has dropped its end of the channel, meaning nobody is
+    /// waiting for the result of the associated command anymore.
+    pub(crate) fn is_canceled(&self) -> bool {
+        self.0.is_canceled()
+    }
+
+    /// Deliver `result` to the caller awaiting it.
+    ///
+    /// Takes `self` by value so that it is impossible to respond to the same request twice.
+    pub(crate) fn respond(self, result: Result<Response, Error>) {
+        // The caller may have stopped polling its receiver; there's nobody left to tell.
+        let _ = self.0.send(result);
+    }
+}
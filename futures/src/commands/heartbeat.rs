@@ -1,7 +1,7 @@
 use amq_protocol::frame::AMQPFrame;
-use lapin_async::connection::Connection;
+use lapin_async::connection::{Connection, RequestId};
 
-use commands::Command;
+use commands::{Command, Response};
 use error::Error;
 
 /// Heartbeat command.
@@ -22,4 +22,10 @@ impl Command for Heartbeat {
 	}
 
 	fn has_finished(&self, _conn: &mut Connection) -> bool { true }
+
+	fn request_id(&self) -> Option<RequestId> { None }
+
+	fn response(&self, _conn: &mut Connection) -> Response { Response::Unit }
+
+	fn reset(&mut self) {}
 }
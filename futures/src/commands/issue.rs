@@ -1,28 +1,75 @@
-use futures::{Async, Future, Poll};
+use futures::{sink, Async, Future, Poll, Sink};
+use futures::sync::oneshot;
+use std::time::{Duration, Instant};
 
-use commands::Command;
+use commands::{Command, CommandSender, Responder, Response};
+use error::{Error, ErrorKind};
 
-pub(crate) struct IssueFuture<C>
-where
-	C: Command
-{
-	command: C,
+enum State {
+    /// Submitting the command (and its paired `Responder`) over the command channel.
+    Sending(sink::Send<CommandSender>, oneshot::Receiver<Result<Response, Error>>),
+    /// Waiting for the background task to fire the `Responder`.
+    Waiting(oneshot::Receiver<Result<Response, Error>>),
 }
 
-impl<C> IssueFuture<C>
-where
-	C: Command
-{
-	pub(crate) fn new(command: C) -> Self {
-		IssueFuture {
-			command,
-		}
-	}
+/// Drives a single `Command` to completion against the background task.
+///
+/// This is the one future every command-issuing call builds on: it submits the command over the
+/// command channel (applying the same backpressure as every other command, courtesy of the
+/// bounded `mpsc::Sender`), then waits for the `Responder` registered for it to fire. Dropping an
+/// `IssueFuture` before it resolves drops its `oneshot::Receiver`, which in turn lets the
+/// background task notice the caller is gone and stop tracking the command.
+#[must_use = "futures do nothing unless polled"]
+pub(crate) struct IssueFuture {
+    state: Option<State>,
 }
 
-impl<C> Future for IssueFuture<C>
-where
-	C: Command
-{
-	fn poll(&mut self) -> Poll<Self::Item, Self::Error> {}
+impl IssueFuture {
+    /// Submit `command` over `chan` and return a future resolving to its `Response`.
+    pub(crate) fn new(chan: CommandSender, command: Box<dyn Command>) -> Self {
+        IssueFuture::submit(chan, command, None)
+    }
+
+    /// Like `new`, but fails the future with `ErrorKind::DeadlineExceeded` if the broker hasn't
+    /// replied within `deadline` of this call.
+    pub(crate) fn with_deadline(chan: CommandSender, command: Box<dyn Command>, deadline: Duration) -> Self {
+        IssueFuture::submit(chan, command, Some(Instant::now() + deadline))
+    }
+
+    fn submit(chan: CommandSender, command: Box<dyn Command>, deadline: Option<Instant>) -> Self {
+        let (responder, receiver) = Responder::pair();
+        let send = chan.send((command, responder, deadline));
+        IssueFuture { state: Some(State::Sending(send, receiver)) }
+    }
+}
+
+impl Future for IssueFuture {
+    type Item = Response;
+
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.state.take().expect("IssueFuture polled after completion") {
+                State::Sending(mut send, receiver) => match send.poll() {
+                    Ok(Async::Ready(_)) => self.state = Some(State::Waiting(receiver)),
+                    Ok(Async::NotReady) => {
+                        self.state = Some(State::Sending(send, receiver));
+                        return Ok(Async::NotReady);
+                    },
+                    Err(_) => return Err(ErrorKind::HandleDropped.into()),
+                },
+                State::Waiting(mut receiver) => {
+                    return match receiver.poll() {
+                        Ok(Async::Ready(result)) => result.map(Async::Ready),
+                        Ok(Async::NotReady) => {
+                            self.state = Some(State::Waiting(receiver));
+                            Ok(Async::NotReady)
+                        },
+                        Err(_) => Err(ErrorKind::HandleDropped.into()),
+                    };
+                },
+            }
+        }
+    }
 }
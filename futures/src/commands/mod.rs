@@ -1,6 +1,17 @@
 /// The base `Command` trait.
 mod command;
-pub(crate) use self::command::Command;
+pub(crate) use self::command::{Command, Response};
+
+/// The in-flight request bookkeeping shared between callers and the background task.
+mod dispatch;
+pub(crate) use self::dispatch::{CommandReceiver, CommandSender, Responder};
+
+/// The future that drives a `Command` to completion against the background task.
+mod issue;
+pub(crate) use self::issue::IssueFuture;
+
+/// `basic.*` related commands.
+pub(crate) mod basic;
 
 /// Channel related commands.
 pub(crate) mod channel;
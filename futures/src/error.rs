@@ -14,6 +14,12 @@ pub(crate) enum ErrorKind {
     Transport(io::Error),
     ChannelLimitReached,
     ProtocolError(lapin_async::error::Error),
+    HeartbeatTimeout,
+    DeadlineExceeded,
+    /// The connection was lost and the `ReconnectStrategy` gave up retrying. Carries the
+    /// formatted cause rather than the original `Error`, since `Error` isn't `Clone` and this
+    /// needs to be reported to every command still waiting on the dropped connection.
+    ConnectionLost(String),
 }
 
 impl fmt::Display for Error {
@@ -38,6 +44,9 @@ impl fmt::Display for ErrorKind {
             ErrorKind::Transport(e) => write!(f, "an error occured in the transport: {}", e),
             ErrorKind::ChannelLimitReached => write!(f, "open channel limit reached"),
             ErrorKind::ProtocolError(e) => write!(f, "a protocol error occured: {:?}", e),
+            ErrorKind::HeartbeatTimeout => write!(f, "no frame was received from the broker within twice the negotiated heartbeat timeout"),
+            ErrorKind::DeadlineExceeded => write!(f, "the command's deadline elapsed before the broker replied"),
+            ErrorKind::ConnectionLost(cause) => write!(f, "lost connection to RabbitMQ and gave up reconnecting: {}", cause),
         }
     }
 }
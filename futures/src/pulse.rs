@@ -1,23 +1,28 @@
 use futures::{task, sink, Async, Future, Poll, Sink, Stream};
-use futures::sync::mpsc;
 use std::time::{Instant, Duration};
 use tokio_timer::Interval;
 
-use commands::{self, Command};
+use commands::{self, CommandReceiver, CommandSender, Responder};
 use error::{Error, ErrorKind};
 
 /// A future that sends a heartbeat frame at the given interval.
+///
+/// When `interval` is `None` (the broker negotiated a heartbeat value of `0`, i.e. disabled the
+/// heartbeat), the pulse never fires.
 #[must_use = "futures do nothing unless polled"]
 pub(crate) struct Pulse {
-    interval: Interval,
-    chan: mpsc::Sender<Box<dyn Command>>,
-    task: Option<sink::Send<mpsc::Sender<Box<dyn Command>>>>
+    interval: Option<Interval>,
+    chan: CommandSender,
+    task: Option<sink::Send<CommandSender>>
 }
 
 impl Pulse {
     /// Create a new `Pulse` future instance.
-    pub(crate) fn new(interval: Duration, chan: mpsc::Sender<Box<dyn Command>>) -> Self {
-        let interval = Interval::new(Instant::now(), interval);
+    ///
+    /// `interval` should be half the heartbeat timeout negotiated with the broker, as recommended
+    /// by the AMQP spec, so that two heartbeats are sent within a single timeout window.
+    pub(crate) fn new(interval: Option<Duration>, chan: CommandSender) -> Self {
+        let interval = interval.map(|interval| Interval::new(Instant::now(), interval));
         Pulse {
             interval,
             chan,
@@ -43,11 +48,19 @@ impl Future for Pulse {
                 Err(_) => error!("Couldn't send the heartbeat to the background task"),
             };
         }
-        match self.interval.poll() {
+        let interval = match self.interval {
+            Some(ref mut interval) => interval,
+            None => return Ok(Async::NotReady),
+        };
+        match interval.poll() {
             Ok(Async::NotReady) => Ok(Async::NotReady),
             Ok(Async::Ready(Some(_))) => {
                 let heartbeat = commands::heartbeat::Heartbeat::new();
-                self.task = Some(self.chan.clone().send(Box::new(heartbeat)));
+                // Nobody awaits the heartbeat's result, so the paired receiver is dropped
+                // immediately; `Heartbeat::request_id` returns `None`, so it never enters the
+                // in-flight table.
+                let (responder, _) = Responder::pair();
+                self.task = Some(self.chan.clone().send((Box::new(heartbeat), responder, None)));
                 Ok(Async::NotReady)
             },
             Ok(Async::Ready(None)) => Ok(Async::Ready(())),
@@ -65,10 +78,21 @@ impl Future for Pulse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::sync::mpsc;
     use test_support::*;
 
     use tokio::runtime::current_thread::Runtime;
 
+    /// Asserts that `rx` is ready with a heartbeat command, consuming its paired responder.
+    fn assert_heartbeat_sent(rx: &mut CommandReceiver) {
+        match rx.poll() {
+            Ok(Async::Ready(Some((command, _responder, _deadline)))) => {
+                assert_eq!(format!("{:?}", command), "Heartbeat");
+            },
+            other => panic!("expected a heartbeat command, got {:?}", other),
+        }
+    }
+
     #[test]
     fn test_pulse_sending_message_at_configured_interval() {
         mocked(|timer, time| {
@@ -76,7 +100,7 @@ mod tests {
             let duration = Duration::from_secs(60);
             let interval = Interval::new(time.now(), duration);
             let mut pulse = Pulse {
-                interval,
+                interval: Some(interval),
                 chan: tx,
                 task: None,
             };
@@ -88,7 +112,7 @@ mod tests {
 
             // Pulse should have send command to channel
             assert_not_ready!(pulse);
-            assert_ready_eq!(rx, Some(()));
+            assert_heartbeat_sent(&mut rx);
 
             // Should not enqueue task if called before interval duration
             assert_not_ready!(pulse);
@@ -104,7 +128,40 @@ mod tests {
 
             // Pulse should have send command to channel
             assert_not_ready!(pulse);
-            assert_ready_eq!(rx, Some(()));
+            assert_heartbeat_sent(&mut rx);
+        });
+    }
+
+    #[test]
+    fn test_pulse_negotiated_interval_is_half_the_broker_timeout() {
+        mocked(|timer, time| {
+            let (tx, mut rx) = mpsc::channel(16);
+            let negotiated_timeout = Duration::from_secs(30);
+            let mut pulse = Pulse::new(Some(negotiated_timeout / 2), tx);
+
+            // Nothing sent yet.
+            assert_not_ready!(pulse);
+            assert_not_ready!(rx);
+
+            // A heartbeat is due after half the negotiated timeout, not the full timeout.
+            advance(timer, negotiated_timeout / 2);
+            assert_not_ready!(pulse);
+            assert_heartbeat_sent(&mut rx);
+        });
+    }
+
+    #[test]
+    fn test_pulse_disabled_never_sends_a_heartbeat() {
+        mocked(|timer, _time| {
+            let (tx, mut rx) = mpsc::channel(16);
+            let mut pulse = Pulse::new(None, tx);
+
+            assert_not_ready!(pulse);
+            assert_not_ready!(rx);
+
+            advance(timer, Duration::from_secs(3600));
+            assert_not_ready!(pulse);
+            assert_not_ready!(rx);
         });
     }
 }
@@ -0,0 +1,55 @@
+use std::time::Duration;
+
+/// Controls how the background task reacts to a broken connection to the broker.
+///
+/// The default, [`Never`](#variant.Never), preserves lapin's historical behaviour: a transport
+/// error tears down the background task and every channel along with it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReconnectStrategy {
+    /// Give up as soon as the transport reports an error.
+    Never,
+    /// Wait a fixed amount of time between reconnection attempts, retrying forever.
+    FixedInterval(Duration),
+    /// Back off exponentially between attempts, up to `max`, giving up after `max_retries`.
+    ExponentialBackoff {
+        base: Duration,
+        max: Duration,
+        max_retries: u32,
+    },
+}
+
+impl Default for ReconnectStrategy {
+    fn default() -> Self {
+        ReconnectStrategy::Never
+    }
+}
+
+impl ReconnectStrategy {
+    /// The delay to wait before the `attempt`th reconnection attempt (0-indexed), or `None` if
+    /// the strategy has been exhausted and the background task should give up.
+    pub(crate) fn delay(&self, attempt: u32) -> Option<Duration> {
+        match *self {
+            ReconnectStrategy::Never => None,
+            ReconnectStrategy::FixedInterval(interval) => Some(interval),
+            ReconnectStrategy::ExponentialBackoff { base, max, max_retries } => {
+                if attempt >= max_retries {
+                    None
+                } else {
+                    let factor = 2u32.checked_pow(attempt).unwrap_or(u32::max_value());
+                    Some(base.checked_mul(factor).unwrap_or(max).min(max))
+                }
+            },
+        }
+    }
+}
+
+/// The state of the connection to the broker, as observed from outside the background task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The transport is up and commands are being submitted to the broker.
+    Connected,
+    /// The transport dropped and the background task is waiting to retry or reconnecting.
+    Reconnecting,
+    /// The configured `ReconnectStrategy` was exhausted; the background task has stopped.
+    Failed,
+}